@@ -0,0 +1,1012 @@
+use std::cell::LazyCell;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::Write as _;
+use std::fs::{File, create_dir_all};
+use std::io::Read as _;
+use std::io::{BufWriter, Error as IoError};
+use std::io::Write as _;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use anyhow::Context;
+use camino::{Utf8Path, Utf8PathBuf};
+use chrono::{DateTime, Local, Utc};
+use handlebars::Context as HbsContext;
+use handlebars::Handlebars;
+use handlebars::Helper;
+use handlebars::HelperDef;
+use handlebars::JsonValue;
+use handlebars::Output;
+use handlebars::RenderContext;
+use handlebars::RenderError;
+use handlebars::RenderErrorReason;
+use handlebars::ScopedJson;
+use handlebars::TemplateError;
+use itertools::Itertools;
+use log::trace;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use toml::Value;
+use toml::map::Map;
+use xz2::stream::{Check, Filters, LzmaOptions, Stream};
+use xz2::write::XzEncoder;
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Receipe {
+    #[serde(default)]
+    packages: Vec<String>,
+    #[serde(default)]
+    systemd: Vec<String>,
+    #[serde(default)]
+    template_vars: Vec<toml::Table>,
+    #[serde(default)]
+    steps: Vec<Step>,
+    #[serde(default)]
+    depends: Vec<String>,
+    #[serde(default)]
+    hooks: Vec<Hook>,
+}
+
+/// A trigger that runs once, after every recipe has been processed, if any
+/// installed file's destination matched `match`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Hook {
+    #[serde(rename = "match")]
+    matches: String,
+    run: String,
+}
+
+/// Settings shared by every recipe, loaded from `cocinero.toml` at the root
+/// of the recipes directory if present.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct GlobalConfig {
+    #[serde(default)]
+    hooks: Vec<Hook>,
+}
+
+impl GlobalConfig {
+    fn load(receipes_dir: &Utf8Path) -> anyhow::Result<Self> {
+        let config_path = receipes_dir.join("cocinero.toml");
+        if !config_path.try_exists()? {
+            return Ok(Self::default());
+        }
+        let s = std::fs::read_to_string(&config_path)
+            .with_context(|| format!("While reading {config_path}"))?;
+        toml::from_str(&s).with_context(|| format!("While parsing {config_path}"))
+    }
+}
+
+/// Everything a run of cocinero installed onto the target: the final
+/// destination of every file it wrote, and every systemd unit it enabled.
+/// Persisted as `manifest.toml` in the target dir so `uncook.sh` can reverse
+/// the run, and so the next run can prune files an edited receipe no longer
+/// produces.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Manifest {
+    #[serde(default)]
+    files: Vec<Utf8PathBuf>,
+    #[serde(default)]
+    systemd_units: Vec<String>,
+}
+
+impl Manifest {
+    pub fn load(target: &Utf8Path) -> anyhow::Result<Self> {
+        let manifest_path = target.join("manifest.toml");
+        if !manifest_path.try_exists()? {
+            return Ok(Self::default());
+        }
+        let s = std::fs::read_to_string(&manifest_path)
+            .with_context(|| format!("While reading {manifest_path}"))?;
+        toml::from_str(&s).with_context(|| format!("While parsing {manifest_path}"))
+    }
+
+    fn write(&self, target: &Utf8Path) -> anyhow::Result<()> {
+        let manifest_path = target.join("manifest.toml");
+        let s = toml::to_string_pretty(self)?;
+        std::fs::write(&manifest_path, s)
+            .with_context(|| format!("While writing {manifest_path}"))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+#[serde(tag = "kind")]
+#[serde(deny_unknown_fields)]
+enum Step {
+    #[serde(alias = "copy")]
+    Install {
+        #[serde(default)]
+        template: bool,
+        #[serde(flatten)]
+        install: Install,
+    },
+    Shell {
+        #[serde(default)]
+        template: bool,
+        cmd: String,
+    },
+    Run {
+        #[serde(default)]
+        template: bool,
+        script: Utf8PathBuf,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Install {
+    src: Utf8PathBuf,
+    dest: Utf8PathBuf,
+    mode: Option<String>,
+}
+
+#[derive(Debug, Error)]
+enum ReceipeLoadError {
+    #[error("No receipe")]
+    NoReceipe,
+    #[error("Io error {error} happened while {context}")]
+    Io { error: IoError, context: String },
+    #[error("Couldn't parse receipe")]
+    ReceipeParseError(#[from] toml::de::Error),
+    #[error("Receipe {receipe} depends on unknown receipe {dependency}")]
+    UnknownDependency { receipe: String, dependency: String },
+    #[error("Dependency cycle among receipes: {}", .members.join(", "))]
+    DependencyCycle { members: Vec<String> },
+}
+
+impl ReceipeLoadError {
+    fn io_context(context: impl Into<String>) -> impl FnOnce(IoError) -> ReceipeLoadError {
+        let context = context.into();
+        |error| ReceipeLoadError::Io { error, context }
+    }
+}
+
+impl Receipe {
+    fn try_load(receipe_toml_path: impl AsRef<Path>) -> Result<Self, ReceipeLoadError> {
+        if !receipe_toml_path
+            .as_ref()
+            .try_exists()
+            .map_err(ReceipeLoadError::io_context("Checking if path exists"))?
+        {
+            return Err(ReceipeLoadError::NoReceipe);
+        }
+        let receipe = {
+            let s = std::fs::read_to_string(receipe_toml_path)
+                .map_err(ReceipeLoadError::io_context("Loading receipe"))?;
+            toml::from_str(&s)?
+        };
+        Ok(receipe)
+    }
+}
+
+/// Where a [`Cookbook`] loads its recipes from.
+pub enum ReceipeSource {
+    /// A directory containing one subdirectory per recipe, each with a
+    /// `receipe.toml`, plus an optional `cocinero.toml` for global config.
+    Dir(Utf8PathBuf),
+    /// A single recipe, read as TOML from stdin. Lets cocinero be driven in
+    /// a pipeline; steps that reference files on disk resolve relative to
+    /// the current directory.
+    Stdin,
+}
+
+/// The parsed recipes cocinero will compile into a `cook.sh`.
+pub struct Cookbook {
+    receipes: HashMap<Utf8PathBuf, Receipe>,
+    /// The directory each receipe's `src`/`script` paths resolve against,
+    /// keyed the same as `receipes`. Kept per-receipe (rather than a single
+    /// base dir joined with the receipe name) since the stdin-sourced
+    /// receipe doesn't live in a subdirectory of anything.
+    receipe_dirs: HashMap<Utf8PathBuf, Utf8PathBuf>,
+    global_config: GlobalConfig,
+}
+
+impl Cookbook {
+    pub fn load(source: ReceipeSource) -> anyhow::Result<Self> {
+        match source {
+            ReceipeSource::Dir(dir) => {
+                let receipes = load_all_receipes(&dir)?;
+                let global_config = GlobalConfig::load(&dir)?;
+                let receipe_dirs = receipes
+                    .keys()
+                    .map(|name| (name.clone(), dir.join(name)))
+                    .collect();
+                Ok(Self {
+                    receipes,
+                    receipe_dirs,
+                    global_config,
+                })
+            }
+            ReceipeSource::Stdin => {
+                let mut s = String::new();
+                std::io::stdin()
+                    .read_to_string(&mut s)
+                    .context("While reading receipe from stdin")?;
+                let receipe: Receipe =
+                    toml::from_str(&s).context("While parsing receipe from stdin")?;
+                let name = Utf8PathBuf::from("stdin");
+                let mut receipes = HashMap::new();
+                receipes.insert(name.clone(), receipe);
+                let mut receipe_dirs = HashMap::new();
+                receipe_dirs.insert(name, Utf8PathBuf::from("."));
+                Ok(Self {
+                    receipes,
+                    receipe_dirs,
+                    global_config: GlobalConfig::default(),
+                })
+            }
+        }
+    }
+
+    /// Compiles the recipes into `cook.sh`, writing its contents to `out`,
+    /// and the rest of the target tree (copied sources, rendered templates,
+    /// per-receipe `_cook.sh` scripts, `manifest.toml` and `uncook.sh`)
+    /// under `target`, which must already exist and be empty.
+    ///
+    /// `previous_manifest` is the manifest of a prior run against the same
+    /// `target`, if any, used to prune files a receipe no longer produces.
+    pub fn compile(
+        &self,
+        target: &Utf8Path,
+        previous_manifest: &Manifest,
+        root: &Utf8Path,
+        out: &mut impl std::io::Write,
+    ) -> anyhow::Result<Manifest> {
+        writeln!(out, "echo 'starting to cook'")?;
+
+        let mut template_env = LazyCell::new(TemplateEnv::new);
+
+        let packages = self.receipes.values().flat_map(|u| &u.packages);
+        for package_chunk in &packages.chunks(64) {
+            write!(out, "apt-get install")?;
+            for pkg in package_chunk {
+                write!(out, " {pkg}")?;
+            }
+            writeln!(out,)?;
+        }
+        writeln!(out)?;
+        let receipe_order = topo_sort_receipes(&self.receipes)?;
+        let mut triggered_hooks: BTreeSet<String> = BTreeSet::new();
+        let mut installed_files: Vec<Utf8PathBuf> = Vec::new();
+        for receipe_dir_name in &receipe_order {
+            let receipe = &self.receipes[receipe_dir_name];
+            if receipe.steps.is_empty() {
+                continue;
+            }
+            let hooks: Vec<&Hook> = self
+                .global_config
+                .hooks
+                .iter()
+                .chain(&receipe.hooks)
+                .collect();
+            let orig_receipe_dir_path = self.receipe_dirs[receipe_dir_name].clone();
+            let target_receipe_dir_path = target.join(receipe_dir_name);
+            std::fs::create_dir_all(&target_receipe_dir_path)?;
+            let receipe_script_path = target_receipe_dir_path.join("_cook.sh");
+            let mut receipe_script = create_script(&receipe_script_path)?;
+            let mut install_ctx = InstallCtx {
+                orig_receipe_dir_path: &orig_receipe_dir_path,
+                target_receipe_dir_path: &target_receipe_dir_path,
+                root,
+                hooks: &hooks,
+                triggered_hooks: &mut triggered_hooks,
+                installed_files: &mut installed_files,
+            };
+            writeln!(out, r#"echo 'running receipe "{receipe_dir_name}"'"#)?;
+            writeln!(out, "(cd {receipe_dir_name} && ./_cook.sh)")?;
+            for step in &receipe.steps {
+                match step {
+                    Step::Install {
+                        template: false,
+                        install,
+                    } => perform_install_file(&mut receipe_script, &mut install_ctx, install)?,
+                    Step::Install {
+                        template: true,
+                        install,
+                    } => {
+                        perform_template_install(
+                            &mut receipe_script,
+                            &mut install_ctx,
+                            install,
+                            &mut template_env,
+                            &receipe.template_vars,
+                        )?;
+                    }
+                    Step::Shell {
+                        template: false,
+                        cmd,
+                    } => {
+                        writeln!(&mut receipe_script, "{cmd}")?;
+                    }
+                    Step::Shell {
+                        template: true,
+                        cmd,
+                    } => {
+                        template_env.set_base_dir(&orig_receipe_dir_path);
+                        let cmd_tmplt = template_env.register_template_string(cmd)?;
+                        for vars in &receipe.template_vars {
+                            template_env.render_to_write(&cmd_tmplt, vars, &mut receipe_script)?;
+                            writeln!(&mut receipe_script)?;
+                        }
+                    }
+                    Step::Run {
+                        template: false,
+                        script,
+                    } => {
+                        let src_path = orig_receipe_dir_path.join(script);
+                        let target_path = target_receipe_dir_path.join(script);
+                        copy_create_dir(src_path, target_path)?;
+                        writeln!(&mut receipe_script, "./{script}")?;
+                    }
+                    Step::Run {
+                        template: true,
+                        script,
+                    } => {
+                        template_env.set_base_dir(&orig_receipe_dir_path);
+                        let src_path = orig_receipe_dir_path.join(script);
+                        let file_template = template_env.register_template_file(src_path)?;
+                        for (i, vars) in receipe.template_vars.iter().enumerate() {
+                            let dest_name = script.with_added_extension(format!("{i}"));
+                            let target_path = target_receipe_dir_path.join(&dest_name);
+                            let mut f = new_buf_file(target_path)?;
+                            template_env.render_to_write(&file_template, vars, &mut f)?;
+                            chmod_plus_x(f.get_mut())?;
+                            writeln!(&mut receipe_script, "./{dest_name}")?;
+                        }
+                    }
+                }
+            }
+        }
+        writeln!(out,)?;
+        let mut enabled_units: Vec<String> = Vec::new();
+        for receipe in self.receipes.values() {
+            for unit in &receipe.systemd {
+                enable_unit(out, unit, root)?;
+                enabled_units.push(unit.clone());
+            }
+        }
+        if !triggered_hooks.is_empty() {
+            writeln!(out,)?;
+            writeln!(out, "echo 'running post-install hooks'")?;
+            for run in &triggered_hooks {
+                writeln!(out, "{run}")?;
+            }
+        }
+
+        let stale_units: Vec<&String> = previous_manifest
+            .systemd_units
+            .iter()
+            .filter(|unit| !enabled_units.contains(unit))
+            .collect();
+        let stale_files: Vec<&Utf8PathBuf> = previous_manifest
+            .files
+            .iter()
+            .filter(|file| !installed_files.contains(file))
+            .collect();
+        if !stale_units.is_empty() || !stale_files.is_empty() {
+            writeln!(out,)?;
+            writeln!(out, "echo 'pruning files no longer produced'")?;
+            for unit in stale_units {
+                disable_unit(out, unit, root)?;
+            }
+            for file in stale_files {
+                writeln!(out, "rm -f {}", under_root(root, file))?;
+            }
+        }
+
+        let manifest = Manifest {
+            files: installed_files.clone(),
+            systemd_units: enabled_units.clone(),
+        };
+        manifest.write(target)?;
+        write_uncook_script(target, &installed_files, &enabled_units, root)?;
+
+        Ok(manifest)
+    }
+}
+
+fn new_buf_file(p: impl AsRef<Path>) -> std::io::Result<BufWriter<File>> {
+    File::create(p).map(BufWriter::new)
+}
+
+fn copy_create_dir(src: impl AsRef<Path>, dst: impl AsRef<Path>) -> anyhow::Result<()> {
+    let src = src.as_ref();
+    let dest = dst.as_ref();
+    std::fs::create_dir_all(dest.parent().unwrap_or(Path::new("/")))?;
+    std::fs::copy(src, dest)?;
+    Ok(())
+}
+
+struct TemplateEnv {
+    inner: Handlebars<'static>,
+    counter: u64,
+    base_dir: Arc<Mutex<Option<Utf8PathBuf>>>,
+}
+
+struct TemplateId(String);
+
+/// Helper backing `{{file_hash}}`/`{{sha256}}`: hashes a path relative to
+/// the receipe directory currently being processed.
+struct FileHashHelper {
+    base_dir: Arc<Mutex<Option<Utf8PathBuf>>>,
+}
+
+impl HelperDef for FileHashHelper {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc HbsContext,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'rc>, RenderError> {
+        let rel_path = h.param(0).and_then(|v| v.value().as_str()).ok_or_else(|| {
+            RenderErrorReason::Other("file_hash/sha256 requires a path argument".to_string())
+        })?;
+        let base_dir = self.base_dir.lock().unwrap();
+        let base_dir = base_dir.as_ref().ok_or_else(|| {
+            RenderErrorReason::Other("file_hash/sha256 used outside of a receipe".to_string())
+        })?;
+        let path = base_dir.join(rel_path);
+        let content = std::fs::read(&path)
+            .map_err(|e| RenderErrorReason::Other(format!("file_hash/sha256: couldn't read {path}: {e}")))?;
+        let digest = Sha256::digest(content);
+        Ok(JsonValue::String(format!("{digest:x}")).into())
+    }
+}
+
+fn env_helper(
+    h: &Helper,
+    _: &Handlebars,
+    _: &HbsContext,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> Result<(), RenderError> {
+    let var_name = h.param(0).and_then(|v| v.value().as_str()).ok_or_else(|| {
+        RenderErrorReason::Other("env requires a variable-name argument".to_string())
+    })?;
+    let value = std::env::var(var_name).map_err(|_| {
+        RenderErrorReason::Other(format!("env: environment variable {var_name} is not set"))
+    })?;
+    out.write(&value)?;
+    Ok(())
+}
+
+fn datetime_helper(
+    now: DateTime<Local>,
+) -> impl for<'reg, 'rc> Fn(
+    &Helper<'rc>,
+    &'reg Handlebars<'reg>,
+    &'rc HbsContext,
+    &mut RenderContext<'reg, 'rc>,
+    &mut dyn Output,
+) -> Result<(), RenderError>
++ Send
++ Sync
++ 'static {
+    move |h, _, _, _, out| {
+        let format = h
+            .param(0)
+            .and_then(|v| v.value().as_str())
+            .unwrap_or("%Y-%m-%d %H:%M:%S");
+        out.write(&now.format(format).to_string())?;
+        Ok(())
+    }
+}
+
+fn datetime_utc_helper(
+    now: DateTime<Utc>,
+) -> impl for<'reg, 'rc> Fn(
+    &Helper<'rc>,
+    &'reg Handlebars<'reg>,
+    &'rc HbsContext,
+    &mut RenderContext<'reg, 'rc>,
+    &mut dyn Output,
+) -> Result<(), RenderError>
++ Send
++ Sync
++ 'static {
+    move |h, _, _, _, out| {
+        let format = h
+            .param(0)
+            .and_then(|v| v.value().as_str())
+            .unwrap_or("%Y-%m-%d %H:%M:%S");
+        out.write(&now.format(format).to_string())?;
+        Ok(())
+    }
+}
+
+impl TemplateEnv {
+    fn new() -> Self {
+        let mut inner = Handlebars::new();
+        inner.set_strict_mode(true);
+        let build_time = Utc::now();
+        let base_dir = Arc::new(Mutex::new(None));
+        inner.register_helper(
+            "datetime",
+            Box::new(datetime_helper(build_time.with_timezone(&Local))),
+        );
+        inner.register_helper("datetime_utc", Box::new(datetime_utc_helper(build_time)));
+        inner.register_helper("env", Box::new(env_helper));
+        let file_hash_helper = FileHashHelper {
+            base_dir: base_dir.clone(),
+        };
+        inner.register_helper("file_hash", Box::new(file_hash_helper));
+        let sha256_helper = FileHashHelper {
+            base_dir: base_dir.clone(),
+        };
+        inner.register_helper("sha256", Box::new(sha256_helper));
+        Self {
+            inner,
+            counter: 0,
+            base_dir,
+        }
+    }
+
+    /// Sets the receipe directory that `file_hash`/`sha256` resolve paths
+    /// against, for the templates about to be rendered.
+    fn set_base_dir(&self, dir: &Utf8Path) {
+        *self.base_dir.lock().unwrap() = Some(dir.to_owned());
+    }
+
+    fn next_template_name(&mut self) -> String {
+        self.counter += 1;
+        format!("template_{}", self.counter)
+    }
+
+    fn register_template_string(
+        &mut self,
+        template: impl AsRef<str>,
+    ) -> Result<TemplateId, TemplateError> {
+        let name = self.next_template_name();
+        self.inner
+            .register_template_string(&name, template.as_ref())?;
+        Ok(TemplateId(name))
+    }
+
+    fn register_template_file(
+        &mut self,
+        template: impl AsRef<Path>,
+    ) -> Result<TemplateId, TemplateError> {
+        let name = self.next_template_name();
+        self.inner
+            .register_template_file(&name, template.as_ref())?;
+        Ok(TemplateId(name))
+    }
+
+    fn render(
+        &self,
+        template_id: &TemplateId,
+        data: &Map<String, Value>,
+    ) -> Result<String, RenderError> {
+        self.inner.render(&template_id.0, data)
+    }
+
+    fn render_to_write(
+        &self,
+        template_id: &TemplateId,
+        data: &Map<String, Value>,
+        w: impl std::io::Write,
+    ) -> Result<(), RenderError> {
+        self.inner.render_to_write(&template_id.0, data, w)
+    }
+}
+
+fn write_uncook_script(
+    target: &Utf8Path,
+    installed_files: &[Utf8PathBuf],
+    enabled_units: &[String],
+    root: &Utf8Path,
+) -> anyhow::Result<()> {
+    let mut uncook = create_script(&target.join("uncook.sh"))?;
+    for unit in enabled_units.iter().rev() {
+        disable_unit(&mut uncook, unit, root)?;
+    }
+    for file in installed_files.iter().rev() {
+        writeln!(uncook, "rm -f {}", under_root(root, file))?;
+    }
+    Ok(())
+}
+
+/// Rewrites an absolute install destination to land under `root` instead
+/// of the live filesystem, for staging a complete tree offline. A `root` of
+/// `/` (the default) leaves `path` unchanged.
+fn under_root(root: &Utf8Path, path: &Utf8Path) -> Utf8PathBuf {
+    if root == "/" {
+        return path.to_owned();
+    }
+    root.join(path.as_str().trim_start_matches('/'))
+}
+
+/// Enables `unit`, starting it immediately when cooking the live system, or
+/// just staging the enablement symlinks under `root` otherwise (a staged
+/// tree isn't running, so there's nothing to start or reload).
+fn enable_unit(out: &mut impl std::io::Write, unit: &str, root: &Utf8Path) -> anyhow::Result<()> {
+    if root.as_str() == "/" {
+        writeln!(out, "systemctl enable --now {unit}")?;
+        writeln!(out, "systemctl reload-or-restart {unit}")?;
+    } else {
+        writeln!(out, "systemctl --root={root} enable {unit}")?;
+    }
+    Ok(())
+}
+
+/// Disables `unit`, the inverse of [`enable_unit`].
+fn disable_unit(out: &mut impl std::io::Write, unit: &str, root: &Utf8Path) -> anyhow::Result<()> {
+    if root.as_str() == "/" {
+        writeln!(out, "systemctl disable --now {unit}")?;
+    } else {
+        writeln!(out, "systemctl --root={root} disable {unit}")?;
+    }
+    Ok(())
+}
+
+/// Streams `target` into a compressed tar.xz archive at `package_path`,
+/// whose top-level entry point is `cook.sh`.
+pub fn package_target(
+    target: &Utf8Path,
+    package_path: &Utf8Path,
+    compression_level: u32,
+    dict_size_mib: u32,
+) -> anyhow::Result<()> {
+    let mut lzma_options = LzmaOptions::new_preset(compression_level)
+        .with_context(|| format!("Unsupported compression level {compression_level}"))?;
+    let dict_size_bytes = dict_size_mib
+        .checked_mul(1024 * 1024)
+        .with_context(|| format!("--dict-size-mib {dict_size_mib} is too large"))?;
+    lzma_options.dict_size(dict_size_bytes);
+    let mut filters = Filters::new();
+    filters.lzma2(&lzma_options);
+    let stream = Stream::new_stream_encoder(&filters, Check::Crc64)?;
+    let archive_file =
+        File::create(package_path).with_context(|| format!("While creating {package_path}"))?;
+    let mut tar_builder = tar::Builder::new(XzEncoder::new_stream(archive_file, stream));
+    tar_builder
+        .append_dir_all(".", target)
+        .with_context(|| format!("While packaging {target}"))?;
+    tar_builder.into_inner()?.finish()?;
+    Ok(())
+}
+
+/// Creates `p` as an executable shell script with the cocinero shebang
+/// preamble, ready to be written to.
+pub fn create_script(p: &Utf8Path) -> anyhow::Result<BufWriter<File>> {
+    create_dir_all(p.parent().unwrap_or(Utf8Path::new("/")))?;
+    let mut buf_file = new_buf_file(p)?;
+    for l in [
+        "#!/usr/bin/bash",
+        "",
+        "# Generated by cocinero",
+        "",
+        "set -e",
+        "",
+    ] {
+        writeln!(buf_file, "{l}")?
+    }
+    let file = buf_file.get_mut();
+    chmod_plus_x(file)?;
+    Ok(buf_file)
+}
+
+fn chmod_plus_x(file: &mut File) -> Result<(), anyhow::Error> {
+    let new_mode = file.metadata()?.permissions().mode() | 0o500;
+    file.set_permissions(PermissionsExt::from_mode(new_mode))?;
+    Ok(())
+}
+
+fn load_all_receipes(receipes_dir: &Utf8Path) -> Result<HashMap<Utf8PathBuf, Receipe>, anyhow::Error> {
+    let mut all_receipes = HashMap::new();
+    for entry in receipes_dir.read_dir()? {
+        let entry = entry?;
+        let path: Utf8PathBuf = entry.path().try_into()?;
+        let metadata = std::fs::metadata(&path)?;
+        let name: Utf8PathBuf = entry.file_name().try_into()?;
+        if !metadata.is_dir() {
+            trace!("Ignoring non directory entry : {}", name);
+            continue;
+        }
+        let receipe_toml_path = path.join("receipe.toml");
+        let receipe = match Receipe::try_load(&receipe_toml_path) {
+            Ok(r) => r,
+            Err(ReceipeLoadError::NoReceipe) => {
+                trace!("Ignoring directory {} without receipe.toml", name);
+                continue;
+            }
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("While parsing receipe {}", receipe_toml_path));
+            }
+        };
+        let duplicate = all_receipes.insert(name, receipe).is_some();
+        debug_assert!(!duplicate, "Duplicate entry: {}", path)
+    }
+    Ok(all_receipes)
+}
+
+/// Orders recipe directory names so that every recipe comes after the
+/// recipes it `depends` on, using Kahn's algorithm. Ties are broken by
+/// name so the order of the per-receipe blocks in `cook.sh` is the same on
+/// every run.
+fn topo_sort_receipes(
+    all_receipes: &HashMap<Utf8PathBuf, Receipe>,
+) -> Result<Vec<Utf8PathBuf>, ReceipeLoadError> {
+    let mut in_degree: HashMap<&Utf8PathBuf, usize> =
+        all_receipes.keys().map(|name| (name, 0)).collect();
+    let mut dependents: HashMap<&Utf8PathBuf, Vec<&Utf8PathBuf>> = HashMap::new();
+    for (name, receipe) in all_receipes {
+        for dependency in &receipe.depends {
+            let dependency_key = all_receipes
+                .keys()
+                .find(|k| k.as_str() == dependency)
+                .ok_or_else(|| ReceipeLoadError::UnknownDependency {
+                    receipe: name.to_string(),
+                    dependency: dependency.clone(),
+                })?;
+            *in_degree.get_mut(name).unwrap() += 1;
+            dependents.entry(dependency_key).or_default().push(name);
+        }
+    }
+
+    let mut ready: BTreeSet<&Utf8PathBuf> = in_degree
+        .iter()
+        .filter(|(_, &degree)| degree == 0)
+        .map(|(&name, _)| name)
+        .collect();
+    let mut order = Vec::with_capacity(all_receipes.len());
+    while let Some(name) = ready.pop_first() {
+        order.push(name.clone());
+        for &dependent in dependents.get(name).into_iter().flatten() {
+            let degree = in_degree.get_mut(dependent).unwrap();
+            *degree -= 1;
+            if *degree == 0 {
+                ready.insert(dependent);
+            }
+        }
+    }
+
+    if order.len() < all_receipes.len() {
+        let mut members: Vec<String> = all_receipes
+            .keys()
+            .filter(|name| !order.contains(name))
+            .map(|name| name.to_string())
+            .collect();
+        members.sort();
+        return Err(ReceipeLoadError::DependencyCycle { members });
+    }
+
+    Ok(order)
+}
+
+/// Per-receipe state threaded through the install helpers: the receipe's
+/// source/target directories plus the hooks subsystem's inputs and output.
+struct InstallCtx<'a> {
+    orig_receipe_dir_path: &'a Utf8Path,
+    target_receipe_dir_path: &'a Utf8Path,
+    root: &'a Utf8Path,
+    hooks: &'a [&'a Hook],
+    triggered_hooks: &'a mut BTreeSet<String>,
+    installed_files: &'a mut Vec<Utf8PathBuf>,
+}
+
+fn perform_template_install(
+    receipe_script: &mut BufWriter<File>,
+    ctx: &mut InstallCtx,
+    install: &Install,
+    template_env: &mut TemplateEnv,
+    template_vars: &[Map<String, Value>],
+) -> Result<(), anyhow::Error> {
+    template_env.set_base_dir(ctx.orig_receipe_dir_path);
+    let Install { src, dest, mode } = install;
+    let orig_src_path = ctx.orig_receipe_dir_path.join(src);
+    let dest_template = template_env.register_template_string(dest)?;
+    let file_template = template_env.register_template_file(orig_src_path)?;
+    for var in template_vars {
+        let dest = template_env.render(&dest_template, var)?;
+        let dest_mangled = dest.replace('/', "__");
+        let target_path = ctx.target_receipe_dir_path.join(&dest_mangled);
+        let target_file = new_buf_file(&target_path)?;
+        template_env.render_to_write(&file_template, var, target_file)?;
+        check_managed_disclaimer(&target_path)?;
+        record_triggered_hooks(&dest, ctx.hooks, ctx.triggered_hooks);
+        ctx.installed_files.push(dest.clone().into());
+        install_in_script(
+            receipe_script,
+            &Install {
+                src: dest_mangled.into(),
+                dest: dest.into(),
+                mode: mode.clone(),
+            },
+            ctx.root,
+        )?;
+    }
+    Ok(())
+}
+
+fn perform_install_file(
+    script: &mut BufWriter<File>,
+    ctx: &mut InstallCtx,
+    install: &Install,
+) -> anyhow::Result<()> {
+    let Install { src, dest, .. } = install;
+    let src_path = ctx.orig_receipe_dir_path.join(src);
+    let target_path = ctx.target_receipe_dir_path.join(src);
+    copy_create_dir(src_path, &target_path)?;
+    check_managed_disclaimer(&target_path)?;
+    record_triggered_hooks(dest.as_str(), ctx.hooks, ctx.triggered_hooks);
+    ctx.installed_files.push(dest.clone());
+    install_in_script(script, install, ctx.root)?;
+    Ok(())
+}
+
+/// Records the `run` command of every hook whose `match` glob matches
+/// `dest`, so it can be emitted once at the end of `cook.sh`.
+fn record_triggered_hooks(dest: &str, hooks: &[&Hook], triggered_hooks: &mut BTreeSet<String>) {
+    for hook in hooks {
+        if glob_match(&hook.matches, dest) {
+            triggered_hooks.insert(hook.run.clone());
+        }
+    }
+}
+
+/// Minimal shell-style glob matcher supporting `*` (matches any sequence of
+/// characters, including none). Good enough for matching install destinations
+/// such as `/usr/share/man/*` or `/usr/share/glib-2.0/schemas/*.xml`.
+///
+/// Matches iteratively rather than by backtracking recursion: a pattern with
+/// several `*`s against a long non-matching text would otherwise blow up
+/// exponentially.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.as_bytes();
+    let text = text.as_bytes();
+    let (mut p, mut t) = (0usize, 0usize);
+    // The last `*` seen in `pattern`, and how much of `text` it currently
+    // covers; backed off to widen the match one byte at a time on mismatch.
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && pattern[p] == b'*' {
+            star = Some((p, t));
+            p += 1;
+        } else if p < pattern.len() && pattern[p] == text[t] {
+            p += 1;
+            t += 1;
+        } else if let Some((star_p, star_t)) = star {
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, t));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&b| b == b'*')
+}
+
+#[cfg(test)]
+mod glob_match_tests {
+    use super::glob_match;
+
+    #[test]
+    fn matches_exact_text() {
+        assert!(glob_match("/etc/foo.conf", "/etc/foo.conf"));
+        assert!(!glob_match("/etc/foo.conf", "/etc/bar.conf"));
+    }
+
+    #[test]
+    fn star_matches_any_suffix() {
+        assert!(glob_match("/usr/share/man/*", "/usr/share/man/man1/ls.1"));
+        assert!(glob_match("/usr/share/man/*", "/usr/share/man/"));
+        assert!(!glob_match("/usr/share/man/*", "/usr/share/doc/ls"));
+    }
+
+    #[test]
+    fn star_matches_empty_sequence() {
+        assert!(glob_match("/etc/*.conf", "/etc/.conf"));
+    }
+
+    #[test]
+    fn star_in_the_middle_matches_across_segments() {
+        assert!(glob_match(
+            "/usr/share/glib-2.0/schemas/*.xml",
+            "/usr/share/glib-2.0/schemas/org.foo.bar.xml"
+        ));
+        assert!(!glob_match(
+            "/usr/share/glib-2.0/schemas/*.xml",
+            "/usr/share/glib-2.0/schemas/org.foo.bar.xml.bak"
+        ));
+    }
+
+    #[test]
+    fn leading_star_matches_any_prefix() {
+        assert!(glob_match("*.service", "/etc/systemd/system/foo.service"));
+        assert!(!glob_match("*.service", "/etc/systemd/system/foo.timer"));
+    }
+
+    #[test]
+    fn many_stars_stay_fast_on_a_non_match() {
+        let pattern = "a*".repeat(28) + "b";
+        let text = "a".repeat(28);
+        let start = std::time::Instant::now();
+        assert!(!glob_match(&pattern, &text));
+        assert!(start.elapsed() < std::time::Duration::from_millis(100));
+    }
+}
+
+fn check_managed_disclaimer(p: &Utf8Path) -> anyhow::Result<()> {
+    let content = std::fs::read_to_string(p)?;
+    if !content.contains("managed by cocinero") {
+        println!(r#"File {} has no "managed by cocinero" disclaimer."#, p);
+    }
+    Ok(())
+}
+
+fn install_in_script(
+    script: &mut BufWriter<File>,
+    install: &Install,
+    root: &Utf8Path,
+) -> Result<(), anyhow::Error> {
+    let Install { dest, mode, src } = install;
+    let mut args = String::new();
+    if let Some(mode) = mode {
+        write!(&mut args, " --mode={mode}").unwrap();
+    }
+    let staged_dest = under_root(root, dest);
+    writeln!(script, "install{args} -D {src} {staged_dest}")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod topo_sort_tests {
+    use super::*;
+
+    fn receipe(depends: &[&str]) -> Receipe {
+        Receipe {
+            packages: Vec::new(),
+            systemd: Vec::new(),
+            template_vars: Vec::new(),
+            steps: Vec::new(),
+            depends: depends.iter().map(|s| s.to_string()).collect(),
+            hooks: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn orders_dependencies_before_dependents() {
+        let mut all_receipes = HashMap::new();
+        all_receipes.insert(Utf8PathBuf::from("a"), receipe(&["b"]));
+        all_receipes.insert(Utf8PathBuf::from("b"), receipe(&[]));
+        let order = topo_sort_receipes(&all_receipes).unwrap();
+        let a_pos = order.iter().position(|n| n == "a").unwrap();
+        let b_pos = order.iter().position(|n| n == "b").unwrap();
+        assert!(b_pos < a_pos);
+    }
+
+    #[test]
+    fn breaks_ties_by_name_for_determinism() {
+        let mut all_receipes = HashMap::new();
+        all_receipes.insert(Utf8PathBuf::from("zebra"), receipe(&[]));
+        all_receipes.insert(Utf8PathBuf::from("apple"), receipe(&[]));
+        all_receipes.insert(Utf8PathBuf::from("mango"), receipe(&[]));
+        let order = topo_sort_receipes(&all_receipes).unwrap();
+        assert_eq!(order, vec!["apple", "mango", "zebra"]);
+    }
+
+    #[test]
+    fn rejects_unknown_dependency() {
+        let mut all_receipes = HashMap::new();
+        all_receipes.insert(Utf8PathBuf::from("a"), receipe(&["missing"]));
+        let err = topo_sort_receipes(&all_receipes).unwrap_err();
+        assert!(matches!(err, ReceipeLoadError::UnknownDependency { .. }));
+    }
+
+    #[test]
+    fn detects_dependency_cycle() {
+        let mut all_receipes = HashMap::new();
+        all_receipes.insert(Utf8PathBuf::from("a"), receipe(&["b"]));
+        all_receipes.insert(Utf8PathBuf::from("b"), receipe(&["a"]));
+        let err = topo_sort_receipes(&all_receipes).unwrap_err();
+        assert!(matches!(err, ReceipeLoadError::DependencyCycle { .. }));
+    }
+}